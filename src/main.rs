@@ -1,6 +1,12 @@
 //! Small amethyst demo app to illustrate sprite ordering
 //!
-//! Press Space to change the order of the sprites.
+//! Press Space to cycle the order of the sprites forward,
+//! or Left Shift+Space to cycle it backward.
+
+use std::cmp::Ordering;
+use std::time::Duration;
+
+use serde::Deserialize;
 
 use amethyst::{
     Logger,
@@ -12,10 +18,14 @@ use amethyst::{
     StateEvent,
     SimpleTrans,
     Trans,
+    Error,
     core::{
         Transform,
         TransformBundle,
         SystemBundle,
+        Time,
+        math::Vector3,
+        frame_limiter::FrameRateLimitStrategy,
     },
     prelude::{
         Config,
@@ -24,9 +34,14 @@ use amethyst::{
         DispatcherBuilder,
         World,
         Builder,
+        Entity,
+        Entities,
         Component,
         DenseVecStorage,
         System,
+        ReadExpect,
+        WriteExpect,
+        Read,
         ReadStorage,
         WriteStorage,
         Join,
@@ -35,6 +50,11 @@ use amethyst::{
         Loader,
         Handle,
         AssetStorage,
+        PrefabData,
+        PrefabLoader,
+        PrefabLoaderSystem,
+        ProgressCounter,
+        RonFormat,
     },
     renderer::{
         DisplayConfig,
@@ -52,10 +72,16 @@ use amethyst::{
         Camera,
         Projection,
         Transparent,
+        SpriteSheet,
+        SpriteSheetFormat,
+        SpriteRender,
     },
     input::{
         is_close_requested,
         is_key_down,
+        InputBundle,
+        InputHandler,
+        StringBindings,
     },
     utils::{
         application_root_dir,
@@ -73,6 +99,7 @@ fn main() -> amethyst::Result<()> {
     // Set up configuration paths
     let application_root = application_root_dir()?;
     let display_config_path = application_root.join("resources/display_config.ron");
+    let bindings_path = application_root.join("assets/bindings.ron");
     let asset_path = application_root.join("assets");
 
     // Load display parameters
@@ -100,14 +127,21 @@ fn main() -> amethyst::Result<()> {
             pipe, Some(display_config))
             .with_sprite_visibility_sorting(&[])
         )?
+        .with_bundle(InputBundle::<StringBindings>::new().with_bindings_from_file(bindings_path)?)?
         .with_bundle(GameBundle)?
         ;
 
-    // Build the game with our game state
+    // Build the game, starting in the loading state so assets
+    // are resolved before the gameplay state ever sees them.
     let mut game = Application::build(
         asset_path,
-        GameState::default()
-    )?.build(game_data_builder)?;
+        LoadingState::default()
+    )?
+        .with_frame_limit(
+            FrameRateLimitStrategy::SleepAndYield(Duration::from_millis(2)),
+            60,
+        )
+        .build(game_data_builder)?;
 
     // Run the game.
     game.run();
@@ -119,7 +153,7 @@ fn main() -> amethyst::Result<()> {
 
 // ===================================================================
 // Game Components
-#[derive(Default)]
+#[derive(Clone, Default, Deserialize)]
 /// Sprite Order component.
 struct SpriteOrder {
     /// Controls which sprite is in front
@@ -129,20 +163,71 @@ impl Component for SpriteOrder {
     type Storage = DenseVecStorage<Self>;
 }
 impl SpriteOrder {
-    /// push the sprite towards the camera.
-    /// if the order reaches max_order,
-    /// this will push the sprite to the back.
-    fn bump_order(&mut self, max_order: i32) {
-        self.order = (self.order + 1) % max_order;
+    /// Moves the sprite one step forward (`direction` = 1) or backward
+    /// (`direction` = -1) through the order, wrapping around at `max_order`.
+    fn bump_order(&mut self, max_order: i32, direction: i32) {
+        self.order = (self.order + direction).rem_euclid(max_order);
+    }
+    /// Registers the type with the given world
+    fn register(world: &mut World) {
+        world.register::<Self>();
+    }
+}
+impl<'a> PrefabData<'a> for SpriteOrder {
+    // Any sprite that cares about ordering also needs Transparent,
+    // so the visibility sort picks it up instead of drawing it in arbitrary order.
+    type SystemData = (
+        WriteStorage<'a, Self>,
+        WriteStorage<'a, Transparent>,
+    );
+    type Result = ();
+
+    fn add_to_entity(
+        &self,
+        entity: Entity,
+        (orders, transparents): &mut Self::SystemData,
+        _entities: &[Entity],
+        _children: &[Entity],
+    ) -> Result<(), Error> {
+        orders.insert(entity, self.clone())?;
+        transparents.insert(entity, Transparent)?;
+        Ok(())
     }
+}
+#[derive(Clone, Default, Deserialize)]
+/// Horizontal scrolling speed for a sprite, in units/second.
+struct Scroll {
+    /// How fast (and which way) the sprite drifts along X.
+    velocity: f32,
+}
+impl Component for Scroll {
+    type Storage = DenseVecStorage<Self>;
+}
+impl Scroll {
     /// Registers the type with the given world
     fn register(world: &mut World) {
         world.register::<Self>();
     }
 }
+impl<'a> PrefabData<'a> for Scroll {
+    type SystemData = WriteStorage<'a, Self>;
+    type Result = ();
+
+    fn add_to_entity(
+        &self,
+        entity: Entity,
+        storage: &mut Self::SystemData,
+        _entities: &[Entity],
+        _children: &[Entity],
+    ) -> Result<(), Error> {
+        storage.insert(entity, self.clone())?;
+        Ok(())
+    }
+}
 /// Registers all the required components
 fn register_components(world: &mut World) {
     SpriteOrder::register(world);
+    Scroll::register(world);
 }
 
 // ===================================================================
@@ -152,31 +237,62 @@ fn add_resources(_world: &mut World) {
 }
 
 // ===================================================================
-// Game Entities
-/// Adds a background sprite (i.e. no sprite order)
-fn add_background(world: &mut World) {
-    let path = "sprites/background.png".to_string();
-    let texture_handle = load_texture_handle(world, &path);
-    world.create_entity()
-        .with(Transform::default())
-        .with(texture_handle.clone())
-        .build();
+// Game Prefabs
+/// Prefab data identifying a sprite within a sprite sheet.
+/// Loads the sheet's texture and RON definition, then resolves to a `SpriteRender`.
+#[derive(Clone, Deserialize)]
+struct SpriteRenderPrefab {
+    /// Path to the sprite sheet's texture, relative to the asset directory.
+    texture: String,
+    /// Path to the sprite sheet's RON definition, relative to the asset directory.
+    sheet: String,
+    /// Which sprite in the sheet this entity should show.
+    number: usize,
 }
-/// Adds a sprite.
-/// Key component here is Transparent,
-/// which informs the sprite ordering system to use z position in displaying this sprite.
-fn add_sprite(world: &mut World, name: &'static str, order: i32) {
-    let path = format!("sprites/{}.png", name);
-    let texture_handle = load_texture_handle(world, &path);
-    let mut my_transform = Transform::default();
-    my_transform.set_y((order as f32) * 20.0); // so the sprites don't block each other completely
-    world.create_entity()
-        .with(SpriteOrder{order})
-        .with(my_transform)
-        .with(texture_handle.clone())
-        .with(Transparent) // We need to tell Amethyst that this sprite has some transparency element
-        .build();
+impl<'a> PrefabData<'a> for SpriteRenderPrefab {
+    type SystemData = (
+        ReadExpect<'a, Loader>,
+        Read<'a, AssetStorage<Texture>>,
+        Read<'a, AssetStorage<SpriteSheet>>,
+        WriteStorage<'a, SpriteRender>,
+        // Shared with `add_scene`'s PrefabLoader call, so the loading state
+        // genuinely waits on these loads instead of just the prefab RON itself.
+        WriteExpect<'a, ProgressCounter>,
+    );
+    type Result = ();
+
+    fn add_to_entity(
+        &self,
+        entity: Entity,
+        (loader, texture_storage, sheet_storage, sprite_renders, progress): &mut Self::SystemData,
+        _entities: &[Entity],
+        _children: &[Entity],
+    ) -> Result<(), Error> {
+        let texture_handle = load_texture_handle(&loader, &texture_storage, &self.texture, progress);
+        let sprite_sheet = load_sprite_sheet_handle(&loader, &sheet_storage, texture_handle, &self.sheet, progress);
+        sprite_renders.insert(entity, SpriteRender { sprite_sheet, sprite_number: self.number })?;
+        Ok(())
+    }
+}
+/// Everything needed to spawn one entity in `assets/scene.ron`:
+/// what to draw, where to put it, and (for sprites that need ordering) a `SpriteOrder`.
+#[derive(Clone, Deserialize, PrefabData)]
+struct ScenePrefab {
+    /// The sprite sheet and sprite index this entity should render.
+    sprite_render: SpriteRenderPrefab,
+    /// Where the entity starts out.
+    transform: Transform,
+    /// Primary sort key used by `SpriteOrderSystem` (camera depth only breaks
+    /// ties between equal orders); absent for entities like the background.
+    #[serde(default)]
+    sprite_order: Option<SpriteOrder>,
+    /// Horizontal drift, if this entity scrolls; absent for stationary entities.
+    #[serde(default)]
+    scroll: Option<Scroll>,
 }
+
+// ===================================================================
+// Game Entities
 /// Adds a camera.
 /// We set the camera far enough back so we have room in which to order our sprites.
 fn add_camera(world: &mut World) {
@@ -190,28 +306,134 @@ fn add_camera(world: &mut World) {
         .with(transform)
         .build();
 }
-/// Adds all the entities to our world.
-fn add_entities(world: &mut World) {
-    add_background(world);
-    add_camera(world);
-    add_sprite(world, "Character Cat Girl", 0);
-    add_sprite(world, "Roof North", 1)
+/// Loads `assets/scene.ron` and spawns every entity it describes
+/// (background, sprites, their transforms and sprite orders). The prefab
+/// load and the texture/sprite-sheet loads it triggers (in
+/// `SpriteRenderPrefab::add_to_entity`) all share the world's
+/// `ProgressCounter`, so a loading state can wait on all of them at once.
+fn add_scene(world: &mut World) {
+    world.insert(ProgressCounter::new());
+    let prefab_handle = world.exec(|(loader, mut progress): (PrefabLoader<'_, ScenePrefab>, WriteExpect<'_, ProgressCounter>)| {
+        loader.load("scene.ron", RonFormat, &mut *progress)
+    });
+    world.create_entity().with(prefab_handle).build();
 }
 
 // ===================================================================
 // Game Systems
-/// A system which updates the z position of a sprite
-/// based on the value of its order component
+/// Half-width of the camera's orthographic view, i.e. where a scrolling sprite wraps.
+const SCROLL_BOUND: f32 = 250.0;
+
+/// Moves every `Scroll`ed sprite along X by its velocity each frame,
+/// wrapping it onto the opposite edge once it drifts past the camera's view.
+struct ScrollSystem;
+impl<'s> System<'s> for ScrollSystem {
+    type SystemData = (
+        ReadExpect<'s, Time>,
+        ReadStorage<'s, Scroll>,
+        WriteStorage<'s, Transform>,
+    );
+    fn run(&mut self, (time, scrolls, mut transforms): Self::SystemData) {
+        let delta = time.delta_seconds();
+        for (scroll, transform) in (&scrolls, &mut transforms).join() {
+            let mut x = transform.translation().x + scroll.velocity * delta;
+            if x > SCROLL_BOUND {
+                x -= 2.0 * SCROLL_BOUND;
+            } else if x < -SCROLL_BOUND {
+                x += 2.0 * SCROLL_BOUND;
+            }
+            transform.set_x(x);
+        }
+    }
+}
+
+/// A system which sorts sprites by their camera-relative depth, with
+/// `SpriteOrder` as the *primary* key among sprites that are otherwise
+/// co-located (the common case here, since every ordered sprite in
+/// `scene.ron` sits at the same depth). Depth only breaks ties between
+/// entities that share the same `order`.
+///
+/// `order` has to win the comparison, not just break ties on depth: the
+/// result is written back into each sprite's transform Z below, so if
+/// depth were primary it would feed into next frame's depth computation
+/// and permanently drown out any further `order` changes once the
+/// sprites' Z values first diverged.
 struct SpriteOrderSystem;
 impl<'s>System<'s> for SpriteOrderSystem {
     type SystemData = (
-       ReadStorage<'s, SpriteOrder>,
+       Entities<'s>,
+       ReadStorage<'s, Camera>,
        WriteStorage<'s, Transform>,
+       ReadStorage<'s, Transparent>,
+       ReadStorage<'s, SpriteOrder>,
+    );
+    fn run(&mut self, (entities, cameras, mut transforms, transparent, sprite_order): Self::SystemData) {
+        let camera_pose = (&cameras, &transforms)
+            .join()
+            .map(|(_, transform)| (*transform.translation(), transform.rotation() * -Vector3::z()))
+            .next();
+        let (camera_translation, camera_forward) = match camera_pose {
+            Some(pose) => pose,
+            None => return,
+        };
+
+        let mut depths: Vec<(Entity, i32, f32)> = Vec::new();
+        for (entity, transform) in (&entities, &transforms).join() {
+            if transparent.contains(entity) {
+                let from_camera = transform.translation() - &camera_translation;
+                let depth = from_camera.dot(&camera_forward);
+                let order = sprite_order.get(entity).map_or(0, |sprite_order| sprite_order.order);
+                depths.push((entity, order, depth));
+            }
+        }
+        // Order first so reordering is always visible; depth only breaks ties.
+        depths.sort_by(|(_, order_a, depth_a), (_, order_b, depth_b)| {
+            order_a.cmp(order_b).then(depth_b.partial_cmp(depth_a).unwrap_or(Ordering::Equal))
+        });
+
+        for (index, (entity, _, _)) in depths.into_iter().enumerate() {
+            if let Some(transform) = transforms.get_mut(entity) {
+                transform.set_z(index as f32);
+            }
+        }
+    }
+}
+
+/// Reads the `"cycle_forward"`/`"cycle_backward"` actions from the
+/// `InputHandler` and bumps every sprite's order when one is freshly
+/// pressed. Tracks the previous press state itself so a held key
+/// only bumps once, the way the old per-event Space handling did.
+#[derive(Default)]
+struct ReorderSystem {
+    forward_down: bool,
+    backward_down: bool,
+}
+impl<'s> System<'s> for ReorderSystem {
+    type SystemData = (
+        Read<'s, InputHandler<StringBindings>>,
+        WriteStorage<'s, SpriteOrder>,
     );
-    // set the z position of the sprite based on the sprite's order #.
-    fn run(&mut self, (sprite_order_set, mut transforms): Self::SystemData) {
-        for (sprite_order, transform) in (&sprite_order_set, &mut transforms).join() {
-            transform.set_z(sprite_order.order as f32);
+    fn run(&mut self, (input, mut sprite_order_set): Self::SystemData) {
+        let forward = input.action_is_down("cycle_forward").unwrap_or(false);
+        let backward = input.action_is_down("cycle_backward").unwrap_or(false);
+        let bump_forward = forward && !self.forward_down;
+        let bump_backward = backward && !self.backward_down;
+        self.forward_down = forward;
+        self.backward_down = backward;
+
+        // cycle_forward is just Space, and cycle_backward is Left Shift+Space, so
+        // both actions are down together whenever Left Shift+Space is pressed.
+        // Check backward first so that chord resolves to backward, not forward.
+        if bump_backward || bump_forward {
+            // Derived from the scene rather than hardcoded, so adding or
+            // removing an ordered sprite in scene.ron can't desync the cycle.
+            let max_order = (&sprite_order_set).join().count() as i32;
+            if max_order > 0 {
+                let direction = if bump_backward { -1 } else { 1 };
+                for sprite_order in (&mut sprite_order_set).join() {
+                    sprite_order.bump_order(max_order, direction);
+                }
+            }
         }
     }
 }
@@ -221,7 +443,10 @@ impl<'s>System<'s> for SpriteOrderSystem {
 struct GameBundle;
 impl<'a, 'b>SystemBundle<'a, 'b> for GameBundle {
     fn build(self, builder: &mut DispatcherBuilder<'a, 'b>) -> amethyst::Result<()> {
-        builder.add(SpriteOrderSystem, "Sprite Order System", &[] );
+        builder.add(PrefabLoaderSystem::<ScenePrefab>::default(), "scene_loader", &[]);
+        builder.add(ReorderSystem::default(), "Reorder System", &["scene_loader"]);
+        builder.add(ScrollSystem, "Scroll System", &["scene_loader"]);
+        builder.add(SpriteOrderSystem, "Sprite Order System", &["scene_loader", "Reorder System", "Scroll System"] );
 
         Ok(())
     }
@@ -229,33 +454,47 @@ impl<'a, 'b>SystemBundle<'a, 'b> for GameBundle {
 
 
 // ===================================================================
-// Game State
+// Loading State
+/// Kicks off the camera and scene setup and tracks every asset load it
+/// triggers (the scene prefab itself and, through it, every sprite's
+/// texture and sprite sheet) in a world `ProgressCounter`, handing off
+/// to `GameState` only once all of them are ready.
 #[derive(Default)]
-struct GameState;
+struct LoadingState;
 
-impl SimpleState for GameState {
+impl SimpleState for LoadingState {
     fn on_start(&mut self, data: StateData<'_, GameData<'_, '_>>) {
         let world = data.world;
         register_components(world);
         add_resources(world);
-        add_entities(world);
+        add_camera(world);
+        add_scene(world);
     }
-    /// Handles the usual quit event. Also,
-    /// if the user presses the space bar,
-    /// it swaps the order of the sprites.
-    fn handle_event(&mut self, data: StateData<'_, GameData<'_, '_>>, event: StateEvent) -> SimpleTrans{
+    fn update(&mut self, data: &mut StateData<'_, GameData<'_, '_>>) -> SimpleTrans {
+        data.data.update(&data.world);
+        if data.world.read_resource::<ProgressCounter>().is_complete() {
+            Trans::Switch(Box::new(GameState::default()))
+        } else {
+            Trans::None
+        }
+    }
+}
+
+// ===================================================================
+// Game State
+/// Assumes the camera, sprites and their assets are already in place;
+/// just drives reordering input and the game dispatcher.
+#[derive(Default)]
+struct GameState;
+
+impl SimpleState for GameState {
+    /// Handles the usual quit event.
+    /// Reordering is handled by `ReorderSystem`, driven by the
+    /// `"cycle_forward"`/`"cycle_backward"` actions instead of a raw key here.
+    fn handle_event(&mut self, _data: StateData<'_, GameData<'_, '_>>, event: StateEvent) -> SimpleTrans{
         if let  StateEvent::Window(event) = event {
             if is_close_requested(&event) || is_key_down(&event, VirtualKeyCode::Escape) {
                 return Trans::Quit
-            } else if is_key_down(&event, VirtualKeyCode::Space) {
-                let world = data.world;
-                // Run through the sprite orders and bump them.
-                // This is an example of running a "System" from within an event response.
-                let mut sprite_order_set = world.write_storage::<SpriteOrder>();
-                for sprite_order in (&mut sprite_order_set).join() {
-                    sprite_order.bump_order(2);
-                }
-                return Trans::None
             }
         }
         Trans::None
@@ -267,14 +506,23 @@ impl SimpleState for GameState {
 }
 
 /// Utility to collect code that loads a sprite texture handle.
-fn load_texture_handle(world: &mut World, path: &String) -> Handle<Texture> {
-    let loader = world.read_resource::<Loader>();
-    let texture_storage = world.read_resource::<AssetStorage<Texture>>();
+fn load_texture_handle(loader: &Loader, texture_storage: &AssetStorage<Texture>, path: &str, progress: &mut ProgressCounter) -> Handle<Texture> {
     loader.load(
-        path.as_ref(),
+        path,
         PngFormat,
         TextureMetadata::srgb_scale(),
-        (),
-        &texture_storage
+        progress,
+        texture_storage
+    )
+}
+/// Loads the sprite sheet RON definition for a given texture,
+/// giving back a handle that entities can reference by sprite index.
+fn load_sprite_sheet_handle(loader: &Loader, sheet_storage: &AssetStorage<SpriteSheet>, texture_handle: Handle<Texture>, ron_path: &str, progress: &mut ProgressCounter) -> Handle<SpriteSheet> {
+    loader.load(
+        ron_path,
+        SpriteSheetFormat,
+        texture_handle,
+        progress,
+        sheet_storage
     )
 }